@@ -0,0 +1,318 @@
+// Parses idl/remote_protocol.x and emits, to OUT_DIR/remote_protocol.rs, a typed Rust struct for
+// each `_args`/`_ret` struct definition, a `REMOTE_PROC_*` constant for every entry in the
+// `remote_procedure` enum, and a procedure -> decoder dispatch table (`TypedCallArgs`/
+// `TypedReplyRet` plus `decode_typed_call_args`/`decode_typed_reply_ret`) covering whichever
+// procedures got a typed struct.
+//
+// This only understands the handful of XDR constructs libvirt's IDL actually uses for the
+// procedures we've typed so far (int/hyper scalars and `remote_nonnull_string`); teach it more
+// of the grammar as `idl/remote_protocol.x` grows. parse_call_body/parse_reply_body fall back to
+// the untyped XdrType workaround for any procedure the dispatch table doesn't cover.
+//
+// PRE-MERGE BLOCKER: this checkout has no Cargo.toml (and no src/lib.rs wiring
+// libvirt_rpc_codec.rs into a crate root), so cargo never actually runs this build script, and
+// none of the code it generates -- or anything in src/libvirt_rpc_codec.rs, including its unit
+// tests -- has been compiled. This isn't hypothetical: the two byte-width bugs fixed in
+// write_xdr_type/write_xdr_uuid and read_xdr_bytes/read_raw_bytes/read_xdr_uuid (every opaque<>/
+// string/uuid field was encoded and decoded 4x too wide, because the per-byte pack/unpack calls
+// went through XdrPrimitive's padded u8 impl instead of the crate's dense opaque helpers) sat in
+// this tree for multiple review rounds without a compiler or `cargo test` run ever catching them.
+// Don't paper over the missing manifest by hand-writing a Cargo.toml/Cargo.lock here: pinning
+// tokio-core/tokio-io/futures and this tree's `xdr` dependency to real, resolvable versions needs
+// registry access this environment doesn't have, and a manifest with guessed versions would claim
+// a verified build that never happened. Whoever has that access should add the manifest, a crate
+// root, and run `cargo build && cargo clippy --all-targets -- -D warnings && cargo test` for real
+// before this merges.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=idl/remote_protocol.x");
+
+    let idl = fs::read_to_string("idl/remote_protocol.x").expect("reading idl/remote_protocol.x");
+    let generated = generate(&idl);
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR set by cargo");
+    let dest = Path::new(&out_dir).join("remote_protocol.rs");
+    fs::write(&dest, generated).expect("writing generated remote_protocol.rs");
+}
+
+struct Field {
+    name: String,
+    rust_type: &'static str,
+    read_expr: String,
+    write_stmt: String,
+}
+
+struct Struct {
+    name: String,
+    fields: Vec<Field>,
+}
+
+fn generate(idl: &str) -> String {
+    let mut out = String::new();
+    out.push_str("// AUTO-GENERATED by build.rs from idl/remote_protocol.x. Do not edit by hand.\n\n");
+
+    let structs = parse_structs(idl);
+    for s in &structs {
+        out.push_str(&render_struct(s));
+        out.push('\n');
+    }
+
+    let procs = parse_procedure_enum(idl);
+    for &(ref name, ref value) in &procs {
+        out.push_str(&format!("#[allow(dead_code)]\npub const {}: i32 = {};\n", name, value));
+    }
+    out.push('\n');
+
+    // A (program, version, procedure) -> decoder table, generated from whichever `_args`/`_ret`
+    // structs `idl/remote_protocol.x` defines. Program and version are checked once up front by
+    // `check_program` rather than folded into this table, since this codebase only ever speaks
+    // to a single program+version (LIBVIRT_PROGRAM/LIBVIRT_PROTO_VERSION); procedure is the only
+    // axis that actually varies call to call.
+    out.push_str(&render_dispatch(&structs, &procs, "_args", "TypedCallArgs"));
+    out.push('\n');
+    out.push_str(&render_dispatch(&structs, &procs, "_ret", "TypedReplyRet"));
+
+    out
+}
+
+// Emits an enum (`enum_name`) with one variant per struct whose name ends in `suffix`, plus a
+// `decode_<enum_name in snake_case>(procedure, reader, max_len)` function dispatching on the
+// REMOTE_PROC_* constant derived from that struct's name, and an `encode` method on the enum that
+// dispatches back to the matched struct's own `encode`. Returns `Ok(None)` for any procedure this
+// IDL excerpt hasn't typed yet, so callers can fall back to the untyped XdrType workaround.
+fn render_dispatch(structs: &[Struct], procs: &[(String, String)], suffix: &str, enum_name: &str) -> String {
+    struct Variant {
+        name: String,
+        struct_name: String,
+        proc_const: String,
+    }
+
+    let variants: Vec<Variant> = structs.iter()
+        .filter(|s| s.name.ends_with(suffix))
+        .map(|s| {
+            let base = s.name[..s.name.len() - suffix.len()].trim_end_matches('_');
+            let base = base.trim_start_matches("remote_");
+            let proc_const = format!("REMOTE_PROC_{}", base.to_uppercase());
+
+            if !procs.iter().any(|&(ref name, _)| name == &proc_const) {
+                panic!("idl/remote_protocol.x defines struct {:?} but no matching {} in \
+                         remote_procedure; keep the IDL excerpt internally consistent",
+                       s.name,
+                       proc_const);
+            }
+
+            Variant {
+                name: to_pascal_case(base),
+                struct_name: to_pascal_case(&s.name),
+                proc_const: proc_const,
+            }
+        })
+        .collect();
+
+    let fn_name = format!("decode_{}", to_snake_case(enum_name));
+
+    let mut out = String::new();
+    out.push_str("#[allow(dead_code)]\n");
+    out.push_str("#[derive(PartialEq, Debug)]\n");
+    out.push_str(&format!("pub enum {} {{\n", enum_name));
+    for v in &variants {
+        out.push_str(&format!("    {}({}),\n", v.name, v.struct_name));
+    }
+    out.push_str("}\n\n");
+
+    out.push_str(&format!("impl {} {{\n", enum_name));
+    out.push_str("    #[allow(dead_code)]\n");
+    out.push_str("    pub fn encode(&self, writer: &mut XdrWriter) {\n");
+    out.push_str("        match *self {\n");
+    for v in &variants {
+        out.push_str(&format!("            {}::{}(ref inner) => inner.encode(writer),\n", enum_name, v.name));
+    }
+    out.push_str("        }\n");
+    out.push_str("    }\n");
+    out.push_str("}\n\n");
+
+    out.push_str("#[allow(dead_code, unused_variables)]\n");
+    out.push_str(&format!("pub fn {}(procedure: i32, reader: &mut XdrReader, max_len: u32) -> Result<Option<{}>, Error> {{\n",
+                          fn_name,
+                          enum_name));
+    out.push_str("    match procedure {\n");
+    for v in &variants {
+        out.push_str(&format!("        {} => Ok(Some({}::{}({}::decode(reader, max_len)?))),\n",
+                              v.proc_const,
+                              enum_name,
+                              v.name,
+                              v.struct_name));
+    }
+    out.push_str("        _ => Ok(None),\n");
+    out.push_str("    }\n");
+    out.push_str("}\n");
+
+    out
+}
+
+fn to_snake_case(pascal: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in pascal.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Scans for `struct NAME {\n field;\n ... \n};` blocks and parses each member line as `TYPE
+// name;`, skipping comments and blank lines.
+fn parse_structs(idl: &str) -> Vec<Struct> {
+    let mut structs = Vec::new();
+    let mut lines = idl.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if !line.starts_with("struct ") {
+            continue;
+        }
+
+        let name = line.trim_start_matches("struct ").trim_end_matches('{').trim().to_string();
+        let mut fields = Vec::new();
+
+        for body_line in lines.by_ref() {
+            let body_line = body_line.trim();
+            if body_line.starts_with("};") {
+                break;
+            }
+            if body_line.is_empty() || body_line.starts_with('/') {
+                continue;
+            }
+            fields.push(parse_field(body_line));
+        }
+
+        structs.push(Struct { name: name, fields: fields });
+    }
+
+    structs
+}
+
+// Panics (failing the build) on a struct member whose type this doesn't understand yet, rather
+// than silently omitting the field: a struct missing a field would desync every field after it
+// that decode()/encode() read off the wire, with no signal at build time that anything was wrong.
+fn parse_field(line: &str) -> Field {
+    let line = line.trim_end_matches(';').trim();
+    let idx = line.rfind(' ')
+        .unwrap_or_else(|| panic!("couldn't parse XDR struct member {:?}: no type/name separator", line));
+    let (xdr_type, field_name) = (line[..idx].trim(), line[idx + 1..].trim());
+
+    let (rust_type, read_expr, write_stmt) = match xdr_type {
+        "unsigned hyper" => {
+            ("u64", "reader.unpack::<u64>()?".to_string(), format!("writer.pack(self.{});", field_name))
+        }
+        "hyper" => ("i64", "reader.unpack::<i64>()?".to_string(), format!("writer.pack(self.{});", field_name)),
+        "unsigned int" => {
+            ("u32", "reader.unpack::<u32>()?".to_string(), format!("writer.pack(self.{});", field_name))
+        }
+        "int" => ("i32", "reader.unpack::<i32>()?".to_string(), format!("writer.pack(self.{});", field_name)),
+        "remote_nonnull_string" => {
+            ("String",
+             "read_xdr_string(reader, max_len)?".to_string(),
+             format!("write_xdr_type(writer, &XdrType::String(self.{}.clone()));", field_name))
+        }
+        other => {
+            panic!("idl/remote_protocol.x has a struct member of unsupported XDR type {:?} \
+                     (member: {:?}); teach build.rs::parse_field about it before using it",
+                   other,
+                   line)
+        }
+    };
+
+    Field {
+        name: field_name.to_string(),
+        rust_type: rust_type,
+        read_expr: read_expr,
+        write_stmt: write_stmt,
+    }
+}
+
+// Scans the `remote_procedure` enum for `NAME = NUMBER` entries.
+fn parse_procedure_enum(idl: &str) -> Vec<(String, String)> {
+    let mut procs = Vec::new();
+    let mut in_enum = false;
+
+    for line in idl.lines() {
+        let line = line.trim();
+        if line.starts_with("enum remote_procedure") {
+            in_enum = true;
+            continue;
+        }
+        if !in_enum {
+            continue;
+        }
+        if line.starts_with('}') {
+            break;
+        }
+        if let Some(eq_idx) = line.find('=') {
+            let name = line[..eq_idx].trim().to_string();
+            let value = line[eq_idx + 1..].trim_end_matches(',').trim().to_string();
+            procs.push((name, value));
+        }
+    }
+
+    procs
+}
+
+fn to_pascal_case(snake: &str) -> String {
+    snake.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn render_struct(s: &Struct) -> String {
+    let rust_name = to_pascal_case(&s.name);
+    let mut out = String::new();
+
+    // Same rationale as the REMOTE_PROC_* consts below: a procedure whose _args/_ret type we
+    // generate isn't necessarily one parse_call_body/parse_reply_body calls yet, so the struct
+    // and its decode/encode can easily go unconstructed and trip `-D warnings`.
+    out.push_str("#[allow(dead_code)]\n");
+    out.push_str("#[derive(PartialEq, Debug)]\n");
+    out.push_str(&format!("pub struct {} {{\n", rust_name));
+    for field in &s.fields {
+        out.push_str(&format!("    pub {}: {},\n", field.name, field.rust_type));
+    }
+    out.push_str("}\n\n");
+
+    out.push_str(&format!("impl {} {{\n", rust_name));
+    // `max_len` isn't used by every generated struct (only ones with a remote_nonnull_string
+    // field read via `read_xdr_string`), so allow it being unused rather
+    // than special-casing the signature per struct.
+    out.push_str("    #[allow(dead_code, unused_variables)]\n");
+    out.push_str("    pub fn decode(reader: &mut XdrReader, max_len: u32) -> Result<Self, Error> {\n");
+    out.push_str(&format!("        Ok({} {{\n", rust_name));
+    for field in &s.fields {
+        out.push_str(&format!("            {}: {},\n", field.name, field.read_expr));
+    }
+    out.push_str("        })\n");
+    out.push_str("    }\n\n");
+
+    out.push_str("    #[allow(dead_code)]\n");
+    out.push_str("    pub fn encode(&self, writer: &mut XdrWriter) {\n");
+    for field in &s.fields {
+        out.push_str(&format!("        {}\n", field.write_stmt));
+    }
+    out.push_str("    }\n");
+    out.push_str("}\n");
+
+    out
+}